@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+
+use tentacle::{context::ProtocolContextMutRef, multiaddr::Multiaddr, SessionId};
+
+/// Abstracts the address book and trust state that backs the discovery
+/// protocol, so the protocol driver in `mod.rs` never has to know whether
+/// addresses ultimately live in the `PeerStore`, a test double, or anywhere
+/// else.
+pub trait AddressManager {
+    fn register(&self, context: &ProtocolContextMutRef, version: &str);
+    fn unregister(&self, context: ProtocolContextMutRef);
+
+    /// Whether `addr` is worth keeping/announcing at all (e.g. not a
+    /// loopback or otherwise unroutable address).
+    fn is_valid_addr(&self, addr: &Multiaddr) -> bool;
+
+    fn add_new_addr(&mut self, session_id: SessionId, addr: Multiaddr);
+    fn add_new_addrs(&mut self, session_id: SessionId, addrs: Vec<Multiaddr>);
+
+    fn misbehave(&mut self, session_id: SessionId, kind: Misbehavior) -> MisbehaveResult;
+
+    fn get_random(&mut self, n: usize) -> Vec<Multiaddr>;
+
+    /// The `n` known peers with smallest XOR distance to `target` (a peer id
+    /// or other 256-bit id being looked up).
+    fn closest(&mut self, target: &[u8], n: usize) -> Vec<Multiaddr>;
+
+    /// Addresses of currently connected consensus (bootnode/validator) peers,
+    /// always eligible for announcing regardless of the announce timer.
+    fn consensus_list(&self) -> Vec<Multiaddr>;
+
+    /// Whether `session_id` has completed the identify handshake yet and, if
+    /// so, whether the remote is on our chain id. Discovery must not trust
+    /// `GetNodes`/`Nodes` on a session until this returns `Done { same_chain: true }`.
+    fn wait_identified(&self, session_id: SessionId) -> IdentifyResult;
+
+    /// If a freshly-opened session at `addr` is awaiting an endpoint
+    /// verification challenge, returns the nonce to `Ping` it with.
+    fn pending_challenge(&mut self, addr: &Multiaddr) -> Option<u64>;
+
+    /// Report a `Pong` carrying `nonce` received from `addr`. Returns
+    /// whether it matched an outstanding challenge; on a match the address
+    /// is promoted into the persistent peer store.
+    fn confirm_verified(&mut self, addr: &Multiaddr, nonce: u64) -> bool;
+
+    /// Let accumulated misbehavior scores heal over time, called
+    /// periodically off the protocol's own notify timer.
+    fn decay_scores(&mut self);
+
+    /// Write the node table back to durable storage, called periodically
+    /// off the notify timer. The discovery protocol does not call it on
+    /// shutdown, so changes since the last tick are not written.
+    fn persist_node_table(&self);
+}
+
+/// Result of asking whether a session has finished identifying itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentifyResult {
+    /// The identify exchange for this session hasn't completed yet.
+    Pending,
+    /// Identify completed; `same_chain` tells whether the remote is on our
+    /// network/chain id.
+    Done { same_chain: bool },
+}
+
+/// Outcome of a `misbehave` report: whether the offending session should be
+/// dropped right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MisbehaveResult {
+    Continue,
+    Disconnect,
+}
+
+impl MisbehaveResult {
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, MisbehaveResult::Disconnect)
+    }
+}
+
+/// Kinds of protocol-level misbehavior a remote peer can be reported for.
+#[derive(Debug, Clone)]
+pub enum Misbehavior {
+    /// Sent `GetNodes` more than once on the same session.
+    DuplicateGetNodes,
+    /// Sent a non-announce `Nodes` message more than once on the same session.
+    DuplicateFirstNodes,
+    /// `Nodes` message carried more items than the announce/non-announce cap allows.
+    TooManyItems { announce: bool, length: usize },
+    /// A single `Node` item carried more addresses than `MAX_ADDRS`.
+    TooManyAddresses(usize),
+    /// Message failed to decode.
+    InvalidData,
+    /// Sent discovery messages while identified as being on a different
+    /// chain id than ours.
+    ChainIdMismatch,
+    /// Replied to an endpoint-verification `Ping` with a wrong (or no
+    /// matching) nonce.
+    BadPongNonce,
+}
+
+/// Tracks which addresses a remote session already knows about, so we don't
+/// keep re-announcing the same addresses to it.
+#[derive(Default, Clone)]
+pub struct AddrKnown(HashSet<Multiaddr>);
+
+impl AddrKnown {
+    pub fn insert(&mut self, addr: &Multiaddr) {
+        self.0.insert(addr.clone());
+    }
+
+    pub fn extend<'a>(&mut self, iter: impl Iterator<Item = &'a Multiaddr>) {
+        for addr in iter {
+            self.0.insert(addr.clone());
+        }
+    }
+
+    pub fn contains(&self, addr: &Multiaddr) -> bool {
+        self.0.contains(addr)
+    }
+}
@@ -0,0 +1,350 @@
+use std::{cmp::Reverse, collections::VecDeque, time::SystemTime};
+
+use tentacle::{multiaddr::Multiaddr, secio::PeerId, utils::extract_peer_id};
+
+/// Kademlia bucket capacity (the "k" in k-bucket).
+pub const BUCKET_SIZE: usize = 16;
+
+#[derive(Clone)]
+struct Entry {
+    peer_id:        PeerId,
+    addr:           Multiaddr,
+    last_seen:      SystemTime,
+    last_connected: Option<SystemTime>,
+    success:        u32,
+    failure:        u32,
+}
+
+/// Eviction/persistence priority: each success over failures counts as much
+/// as 1000 seconds of recency (net successes * 1000 minus age in seconds).
+fn quality(entry: &Entry, now: SystemTime) -> i64 {
+    let age_secs = now
+        .duration_since(entry.last_seen)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    (i64::from(entry.success) - i64::from(entry.failure)) * 1000 - age_secs
+}
+
+/// A single bucket: up to `BUCKET_SIZE` entries, ordered oldest-touched first
+/// so the back is always the most recently touched entry.
+#[derive(Default)]
+struct Bucket {
+    entries: VecDeque<Entry>,
+}
+
+impl Bucket {
+    fn touch_or_insert(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let now = SystemTime::now();
+        if let Some(pos) = self.entries.iter().position(|e| e.peer_id == peer_id) {
+            let mut entry = self.entries.remove(pos).expect("position just checked");
+            entry.addr = addr;
+            entry.last_seen = now;
+            self.entries.push_back(entry);
+            return;
+        }
+
+        if self.entries.len() >= BUCKET_SIZE {
+            self.evict_worst(now);
+        }
+        self.entries.push_back(Entry {
+            peer_id,
+            addr,
+            last_seen: now,
+            last_connected: None,
+            success: 0,
+            failure: 0,
+        });
+    }
+
+    /// Evict the lowest-quality entry instead of always the stalest insertion.
+    fn evict_worst(&mut self, now: SystemTime) {
+        if let Some((worst, _)) = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, e)| quality(e, now))
+        {
+            self.entries.remove(worst);
+        }
+    }
+
+    fn record_outcome(&mut self, peer_id: &PeerId, success: bool) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| &e.peer_id == peer_id) {
+            if success {
+                entry.success += 1;
+                entry.last_connected = Some(SystemTime::now());
+            } else {
+                entry.failure += 1;
+            }
+        }
+    }
+}
+
+/// A node-table entry as written to durable storage (peer id as raw bytes
+/// rather than `PeerId`).
+#[derive(Clone)]
+pub struct PersistedPeerEntry {
+    pub peer_id:        Vec<u8>,
+    pub addr:           Multiaddr,
+    pub last_seen:      SystemTime,
+    pub last_connected: Option<SystemTime>,
+    pub success:        u32,
+    pub failure:        u32,
+}
+
+/// Known peers bucketed by XOR distance to our own id, bucket `i` holding
+/// peers whose distance falls in `[2^i, 2^(i+1))`.
+pub struct KBucketTable {
+    local_id: Vec<u8>,
+    buckets:  Vec<Bucket>,
+}
+
+impl KBucketTable {
+    pub fn new(local_peer_id: &PeerId) -> KBucketTable {
+        let local_id = local_peer_id.as_bytes().to_vec();
+        let bits = local_id.len() * 8;
+        KBucketTable {
+            local_id,
+            buckets: (0..bits).map(|_| Bucket::default()).collect(),
+        }
+    }
+
+    /// Rebuild a table from a previous `snapshot()`. Bucket placement isn't
+    /// stored; it's cheap to recompute from each entry's peer id.
+    pub fn restore(local_peer_id: &PeerId, mut entries: Vec<PersistedPeerEntry>) -> KBucketTable {
+        let mut table = KBucketTable::new(local_peer_id);
+        // Insert stalest first so each bucket keeps its most recently seen
+        // entry at the back, as it would have been before the restart.
+        entries.sort_by_key(|entry| entry.last_seen);
+        for entry in entries {
+            let peer_id = match PeerId::from_bytes(entry.peer_id) {
+                Ok(peer_id) => peer_id,
+                Err(_) => continue,
+            };
+            table.insert(peer_id.clone(), entry.addr);
+            table.with_bucket_mut(&peer_id, |bucket| {
+                if let Some(e) = bucket.entries.iter_mut().find(|e| e.peer_id == peer_id) {
+                    e.last_seen = entry.last_seen;
+                    e.last_connected = entry.last_connected;
+                    e.success = entry.success;
+                    e.failure = entry.failure;
+                }
+            });
+        }
+        table
+    }
+
+    /// Record that `peer_id` is reachable at `addr`, placing it in (or
+    /// refreshing it within) its bucket. A no-op for our own id.
+    pub fn insert(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let other_id = peer_id.as_bytes().to_vec();
+        if let Some(index) = bucket_index(&self.local_id, &other_id) {
+            self.buckets[index].touch_or_insert(peer_id, addr);
+        }
+    }
+
+    /// Record the outcome of a connection attempt to `peer_id`, so eviction
+    /// and persistence can prioritize historically reliable peers.
+    pub fn record_success(&mut self, peer_id: &PeerId) {
+        self.with_bucket_mut(peer_id, |bucket| bucket.record_outcome(peer_id, true));
+    }
+
+    pub fn record_failure(&mut self, peer_id: &PeerId) {
+        self.with_bucket_mut(peer_id, |bucket| bucket.record_outcome(peer_id, false));
+    }
+
+    fn with_bucket_mut(&mut self, peer_id: &PeerId, f: impl FnOnce(&mut Bucket)) {
+        let other_id = peer_id.as_bytes().to_vec();
+        if let Some(index) = bucket_index(&self.local_id, &other_id) {
+            f(&mut self.buckets[index]);
+        }
+    }
+
+    /// Up to `n` addresses spread across buckets (round-robin over non-empty
+    /// buckets, most-recently-seen entries first), for a topologically
+    /// diverse sample instead of a uniform random one.
+    pub fn get_random(&self, n: usize) -> Vec<Multiaddr> {
+        let mut result = Vec::with_capacity(n);
+        let mut taken = vec![0usize; self.buckets.len()];
+        loop {
+            let before = result.len();
+            for (bucket, taken) in self.buckets.iter().zip(taken.iter_mut()) {
+                if result.len() >= n {
+                    return result;
+                }
+                if let Some(entry) = bucket.entries.iter().rev().nth(*taken) {
+                    result.push(entry.addr.clone());
+                    *taken += 1;
+                }
+            }
+            if result.len() == before {
+                // No bucket had anything left to offer this round.
+                return result;
+            }
+        }
+    }
+
+    /// The `n` known peers with smallest XOR distance to `target`.
+    pub fn closest(&self, target: &[u8], n: usize) -> Vec<Multiaddr> {
+        let mut candidates: Vec<(Vec<u8>, &Multiaddr)> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .map(|entry| (xor(target, &entry.peer_id.as_bytes()), &entry.addr))
+            .collect();
+        candidates.sort_by(|(a, _), (b, _)| a.cmp(b));
+        candidates
+            .into_iter()
+            .take(n)
+            .map(|(_, addr)| addr.clone())
+            .collect()
+    }
+
+    /// Every known entry, best (most recent/successful) first, for writing
+    /// back to durable storage.
+    pub fn snapshot(&self) -> Vec<PersistedPeerEntry> {
+        let now = SystemTime::now();
+        let mut entries: Vec<&Entry> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .collect();
+        entries.sort_by_key(|entry| Reverse(quality(entry, now)));
+        entries
+            .into_iter()
+            .map(|entry| PersistedPeerEntry {
+                peer_id:        entry.peer_id.as_bytes().to_vec(),
+                addr:           entry.addr.clone(),
+                last_seen:      entry.last_seen,
+                last_connected: entry.last_connected,
+                success:        entry.success,
+                failure:        entry.failure,
+            })
+            .collect()
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+/// Sort an arbitrary list of addresses (not necessarily ones already in the
+/// table, e.g. `FindNode` replies gathered during a lookup) by the XOR
+/// distance of the peer id each one carries to `target`, closest first.
+/// Addresses we can't extract a peer id from are pushed to the back.
+pub(crate) fn sort_by_distance(target: &[u8], addrs: &mut [Multiaddr]) {
+    addrs.sort_by_cached_key(|addr| {
+        extract_peer_id(addr)
+            .map(|id| xor(target, &id.as_bytes()))
+            .unwrap_or_else(|| vec![0xff; target.len()])
+    });
+}
+
+/// Index of the bucket that `other_id` falls into relative to `local_id`:
+/// the position of the highest set bit of their XOR distance, i.e.
+/// `floor(log2(distance))`. `None` when the ids are identical.
+fn bucket_index(local_id: &[u8], other_id: &[u8]) -> Option<usize> {
+    let distance = xor(local_id, other_id);
+    let total_bits = distance.len() * 8;
+    for (byte_index, byte) in distance.iter().enumerate() {
+        if *byte != 0 {
+            let bit_from_msb = byte_index * 8 + byte.leading_zeros() as usize;
+            return Some(total_bits - 1 - bit_from_msb);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tentacle::secio::SecioKeyPair;
+
+    use super::*;
+
+    fn fresh_peer_id() -> PeerId {
+        SecioKeyPair::secp256k1_generated().public_key().peer_id()
+    }
+
+    fn addr_for(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port)
+            .parse()
+            .expect("valid multiaddr")
+    }
+
+    #[test]
+    fn bucket_index_is_highest_set_bit_of_distance() {
+        // Differ only in the lowest bit -> distance 0b0000_0001 -> bucket 0.
+        assert_eq!(bucket_index(&[0b0000_0000], &[0b0000_0001]), Some(0));
+        // Differ in the top bit -> distance 0b1000_0000 -> bucket 7.
+        assert_eq!(bucket_index(&[0b0000_0000], &[0b1000_0000]), Some(7));
+        // Identical ids have no distance, and therefore no bucket.
+        assert_eq!(bucket_index(&[0xab], &[0xab]), None);
+    }
+
+    #[test]
+    fn closest_orders_by_xor_distance() {
+        let local = fresh_peer_id();
+        let mut table = KBucketTable::new(&local);
+
+        let peers: Vec<(PeerId, Multiaddr)> = (0..4)
+            .map(|i| (fresh_peer_id(), addr_for(4000 + i)))
+            .collect();
+        for (peer, addr) in &peers {
+            table.insert(peer.clone(), addr.clone());
+        }
+
+        let local_id = local.as_bytes();
+        let mut expected = peers.clone();
+        expected.sort_by_key(|(peer, _)| xor(&local_id, &peer.as_bytes()));
+        let expected_addrs: Vec<Multiaddr> =
+            expected.into_iter().map(|(_, addr)| addr).collect();
+
+        assert_eq!(table.closest(&local_id, peers.len()), expected_addrs);
+    }
+
+    #[test]
+    fn eviction_prefers_quality_over_insertion_order() {
+        let mut bucket = Bucket::default();
+        for i in 0..BUCKET_SIZE {
+            bucket.touch_or_insert(fresh_peer_id(), addr_for(5000 + i as u16));
+        }
+        assert_eq!(bucket.entries.len(), BUCKET_SIZE);
+
+        // Give the stalest (first-inserted) entry a strong track record.
+        let reliable = bucket.entries.front().expect("bucket not empty").peer_id.clone();
+        bucket.record_outcome(&reliable, true);
+        bucket.record_outcome(&reliable, true);
+
+        // Plain LRU would evict `reliable` next, since it's the stalest
+        // insertion; quality-based eviction should spare it in favor of one
+        // of the untouched, zero-success entries.
+        bucket.touch_or_insert(fresh_peer_id(), addr_for(6000));
+
+        assert!(bucket.entries.iter().any(|e| e.peer_id == reliable));
+        assert_eq!(bucket.entries.len(), BUCKET_SIZE);
+    }
+
+    #[test]
+    fn snapshot_restore_round_trip_preserves_metadata() {
+        let local = fresh_peer_id();
+        let mut table = KBucketTable::new(&local);
+
+        let peer = fresh_peer_id();
+        table.insert(peer.clone(), addr_for(7000));
+        table.record_success(&peer);
+        table.record_failure(&peer);
+
+        let snapshot = table.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].success, 1);
+        assert_eq!(snapshot[0].failure, 1);
+
+        let restored = KBucketTable::restore(&local, snapshot);
+        assert_eq!(restored.closest(&peer.as_bytes(), 1), vec![addr_for(7000)]);
+
+        let restored_snapshot = restored.snapshot();
+        assert_eq!(restored_snapshot.len(), 1);
+        assert_eq!(restored_snapshot[0].success, 1);
+        assert_eq!(restored_snapshot[0].failure, 1);
+    }
+}
@@ -1,9 +1,10 @@
 mod addr;
+mod kbucket;
 mod protocol;
 mod state;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -15,6 +16,7 @@ use tentacle::{
     bytes,
     context::{ProtocolContext, ProtocolContextMutRef},
     multiaddr::Multiaddr,
+    secio::PeerId,
     traits::ServiceProtocol,
     utils::{extract_peer_id, is_reachable, multiaddr_to_socketaddr},
     SessionId,
@@ -23,12 +25,14 @@ use tentacle::{
 use crate::peer_manager::PeerManager;
 
 pub use self::{
-    addr::{AddrKnown, AddressManager, MisbehaveResult, Misbehavior},
+    addr::{AddrKnown, AddressManager, IdentifyResult, MisbehaveResult, Misbehavior},
+    kbucket::PersistedPeerEntry,
     protocol::{DiscoveryMessage, Node, Nodes},
     state::SessionState,
 };
 use self::{
-    protocol::{GetNodes, Payload},
+    kbucket::KBucketTable,
+    protocol::{FindNode, GetNodes, Payload, Ping, Pong},
     state::RemoteAddress,
 };
 
@@ -40,6 +44,70 @@ const MAX_ADDR_TO_SEND: usize = 1000;
 const MAX_ADDRS: usize = 3;
 // Every 24 hours send announce nodes message
 const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(3600 * 24);
+// Number of peers queried in parallel per round of an iterative FindNode lookup.
+const LOOKUP_ALPHA: usize = 3;
+// Upper bound on the number of rounds a lookup will run before giving up.
+const LOOKUP_MAX_STEPS: usize = 8;
+// How long a discovered address has to answer an endpoint-verification Ping
+// before it's dropped from the pending table.
+const PING_CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+// Caps how many endpoint verifications we'll have in flight at once on
+// behalf of addresses a single session announced to us.
+const MAX_PENDING_PER_SESSION: usize = 16;
+// Cumulative misbehavior score at which a peer is disconnected, by default.
+const DEFAULT_BAN_SCORE_THRESHOLD: u32 = 100;
+// How much a peer's misbehavior score decays per notify tick, by default.
+const DEFAULT_SCORE_DECAY_RATE: u32 = 10;
+
+/// How much a single report of `kind` adds to a peer's misbehavior score.
+/// Small for noisy-but-harmless behavior, large for anything that looks
+/// like an attempt to corrupt the peer store or protocol state.
+fn misbehavior_score(kind: &Misbehavior) -> u32 {
+    match kind {
+        Misbehavior::DuplicateGetNodes | Misbehavior::DuplicateFirstNodes => 10,
+        Misbehavior::TooManyAddresses(_) => 20,
+        Misbehavior::TooManyItems { .. } => 20,
+        Misbehavior::BadPongNonce => 30,
+        Misbehavior::InvalidData => 50,
+        Misbehavior::ChainIdMismatch => 100,
+    }
+}
+
+/// What to do with a `GetNodes`/`Nodes`/`FindNode` message given the
+/// session's identify state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Admission {
+    /// Identify hasn't completed yet; silently ignore the message.
+    Ignore,
+    /// Identify completed but the remote is on a different chain id; report
+    /// it as misbehavior.
+    Reject,
+    /// Identify completed and the remote is on our chain id; handle it.
+    Proceed,
+}
+
+fn admission(same_chain: Option<bool>) -> Admission {
+    match same_chain {
+        None => Admission::Ignore,
+        Some(false) => Admission::Reject,
+        Some(true) => Admission::Proceed,
+    }
+}
+
+/// Whether a `Pong`'s nonce matches the one we challenged the address with.
+fn pong_matches_challenge(expected_nonce: u64, received_nonce: u64) -> bool {
+    expected_nonce == received_nonce
+}
+
+/// Whether a cumulative misbehavior `score` has crossed `threshold` and the
+/// offending session should be disconnected.
+fn misbehave_result(score: u32, threshold: u32) -> MisbehaveResult {
+    if score >= threshold {
+        MisbehaveResult::Disconnect
+    } else {
+        MisbehaveResult::Continue
+    }
+}
 
 pub struct DiscoveryProtocol<M> {
     sessions:                HashMap<SessionId, SessionState>,
@@ -79,6 +147,18 @@ impl<M: AddressManager> ServiceProtocol for DiscoveryProtocol<M> {
 
         self.addr_mgr.register(&context, version);
 
+        // If this session is the result of us dialing a discovered address
+        // to verify it's really reachable, challenge it with a Ping before
+        // trusting anything it tells us.
+        if let Some(nonce) = self.addr_mgr.pending_challenge(&session.address) {
+            let msg = DiscoveryMessage::new_ping(nonce);
+            let mut buf = bytes::BytesMut::with_capacity(msg.encoded_len());
+            msg.encode(&mut buf).unwrap();
+            if context.send_message(buf.freeze()).is_err() {
+                debug!("{:?} send discovery Ping challenge fail", session.id);
+            }
+        }
+
         self.sessions
             .insert(session.id, SessionState::new(context, &self.addr_mgr));
     }
@@ -94,6 +174,23 @@ impl<M: AddressManager> ServiceProtocol for DiscoveryProtocol<M> {
         let session = context.session;
         trace!("[received message]: length={}", data.len());
 
+        // Discovery must not accept or hand out addresses until the session
+        // has proven, via the identify handshake, that it belongs to our
+        // network. Cache the result on the session once known so we don't
+        // have to ask the address manager on every message.
+        let same_chain = match self.sessions.get(&session.id).and_then(|s| s.identified) {
+            Some(same_chain) => Some(same_chain),
+            None => match self.addr_mgr.wait_identified(session.id) {
+                IdentifyResult::Pending => None,
+                IdentifyResult::Done { same_chain } => {
+                    if let Some(state) = self.sessions.get_mut(&session.id) {
+                        state.identified = Some(same_chain);
+                    }
+                    Some(same_chain)
+                }
+            },
+        };
+
         let mgr = &mut self.addr_mgr;
         let mut check = |behavior| -> bool {
             if mgr.misbehave(session.id, behavior).is_disconnect() {
@@ -117,6 +214,21 @@ impl<M: AddressManager> ServiceProtocol for DiscoveryProtocol<M> {
                                 version,
                             })),
                     } => {
+                        match admission(same_chain) {
+                            Admission::Ignore => {
+                                debug!(
+                                    "ignore GetNodes from session [{}]: not identified yet",
+                                    session.id
+                                );
+                                return;
+                            }
+                            Admission::Reject => {
+                                check(Misbehavior::ChainIdMismatch);
+                                return;
+                            }
+                            Admission::Proceed => {}
+                        }
+
                         if let Some(state) = self.sessions.get_mut(&session.id) {
                             if state.received_get_nodes && check(Misbehavior::DuplicateGetNodes) {
                                 return;
@@ -169,6 +281,21 @@ impl<M: AddressManager> ServiceProtocol for DiscoveryProtocol<M> {
                     DiscoveryMessage {
                         payload: Some(Payload::Nodes(nodes)),
                     } => {
+                        match admission(same_chain) {
+                            Admission::Ignore => {
+                                debug!(
+                                    "ignore Nodes from session [{}]: not identified yet",
+                                    session.id
+                                );
+                                return;
+                            }
+                            Admission::Reject => {
+                                check(Misbehavior::ChainIdMismatch);
+                                return;
+                            }
+                            Admission::Proceed => {}
+                        }
+
                         if let Some(misbehavior) = verify_nodes_message(&nodes) {
                             if check(misbehavior) {
                                 return;
@@ -198,6 +325,56 @@ impl<M: AddressManager> ServiceProtocol for DiscoveryProtocol<M> {
                             }
                         }
                     }
+                    DiscoveryMessage {
+                        payload: Some(Payload::FindNode(FindNode { target, count })),
+                    } => {
+                        match admission(same_chain) {
+                            Admission::Ignore => {
+                                debug!(
+                                    "ignore FindNode from session [{}]: not identified yet",
+                                    session.id
+                                );
+                                return;
+                            }
+                            Admission::Reject => {
+                                check(Misbehavior::ChainIdMismatch);
+                                return;
+                            }
+                            Admission::Proceed => {}
+                        }
+
+                        let n = ::std::cmp::min(kbucket::BUCKET_SIZE, count as usize);
+                        let items = self
+                            .addr_mgr
+                            .closest(&target, n)
+                            .into_iter()
+                            .map(|addr| Node::with_addrs(vec![addr]))
+                            .collect::<Vec<_>>();
+
+                        let msg = DiscoveryMessage::new_nodes(false, items);
+                        let mut buf = bytes::BytesMut::with_capacity(msg.encoded_len());
+                        msg.encode(&mut buf).unwrap();
+                        if context.send_message(buf.freeze()).is_err() {
+                            debug!("{:?} send discovery msg Nodes (FindNode reply) fail", session.id)
+                        }
+                    }
+                    DiscoveryMessage {
+                        payload: Some(Payload::Ping(Ping { nonce })),
+                    } => {
+                        let msg = DiscoveryMessage::new_pong(nonce);
+                        let mut buf = bytes::BytesMut::with_capacity(msg.encoded_len());
+                        msg.encode(&mut buf).unwrap();
+                        if context.send_message(buf.freeze()).is_err() {
+                            debug!("{:?} send discovery Pong fail", session.id)
+                        }
+                    }
+                    DiscoveryMessage {
+                        payload: Some(Payload::Pong(Pong { nonce })),
+                    } => {
+                        if !self.addr_mgr.confirm_verified(&session.address, nonce) {
+                            check(Misbehavior::BadPongNonce);
+                        }
+                    }
                     DiscoveryMessage { payload: None } => {}
                 }
             }
@@ -256,6 +433,24 @@ impl<M: AddressManager> ServiceProtocol for DiscoveryProtocol<M> {
                 }
             }
         }
+
+        // Let transient faults heal instead of letting misbehavior scores
+        // only ever climb towards the ban threshold.
+        self.addr_mgr.decay_scores();
+
+        // Write the node table back to durable storage periodically.
+        self.addr_mgr.persist_node_table();
+    }
+}
+
+/// Seed a lookup's shortlist: prefer peers already known to be close to the
+/// target, but fall back to the consensus/bootnode list so a fresh node with
+/// an empty table still has somewhere to start from.
+fn seed_shortlist(closest: Vec<Multiaddr>, consensus_list: Vec<Multiaddr>) -> Vec<Multiaddr> {
+    if closest.is_empty() {
+        consensus_list
+    } else {
+        closest
     }
 }
 
@@ -292,18 +487,107 @@ fn verify_nodes_message(nodes: &Nodes) -> Option<Misbehavior> {
     misbehavior
 }
 
+/// A discovered address that's been dialed to prove it's really reachable,
+/// and is waiting on a matching `Pong` before it's trusted.
+struct PendingVerification {
+    nonce:      u64,
+    // Session that announced this address, so we can bound how many
+    // verifications we'll chase on its behalf at once.
+    session_id: SessionId,
+    expires_at: Instant,
+}
+
 pub struct DiscoveryAddressManager {
     pub discovery_local_address: bool,
+    /// Cumulative misbehavior score at which a peer is disconnected.
+    pub ban_score_threshold:     u32,
+    /// How much a peer's misbehavior score is reduced on each decay tick.
+    pub score_decay_rate:        u32,
     peer_manager:                Arc<PeerManager>,
+    kbuckets:                    KBucketTable,
+    pending:                     HashMap<Multiaddr, PendingVerification>,
 }
 
 impl DiscoveryAddressManager {
-    pub fn new(peer_manager: Arc<PeerManager>) -> Self {
+    pub fn new(peer_manager: Arc<PeerManager>, local_peer_id: &PeerId) -> Self {
+        // Reload whatever was persisted last run instead of starting cold.
+        let snapshot = peer_manager.load_node_table();
+        let kbuckets = if snapshot.is_empty() {
+            KBucketTable::new(local_peer_id)
+        } else {
+            debug!("restoring {} peers from persisted node table", snapshot.len());
+            KBucketTable::restore(local_peer_id, snapshot)
+        };
+
         DiscoveryAddressManager {
             peer_manager,
             discovery_local_address: false,
+            ban_score_threshold: DEFAULT_BAN_SCORE_THRESHOLD,
+            score_decay_rate: DEFAULT_SCORE_DECAY_RATE,
+            kbuckets,
+            pending: HashMap::default(),
         }
     }
+
+    fn evict_expired_pending(&mut self) {
+        let now = Instant::now();
+        self.pending.retain(|_, pending| pending.expires_at > now);
+    }
+
+    /// Iteratively narrow in on the peers closest to `target`: start from
+    /// the `LOOKUP_ALPHA` closest peers we already know (or the consensus
+    /// list, on a fresh node with nothing in its table yet), query each for
+    /// its own closest peers via `FindNode`, fold the replies into the
+    /// shortlist, and repeat against the closest unqueried peers until no
+    /// round turns up anything closer (or `LOOKUP_MAX_STEPS` is hit).
+    pub async fn lookup(&self, target: &[u8]) -> Vec<Multiaddr> {
+        let mut shortlist = seed_shortlist(
+            self.kbuckets.closest(target, LOOKUP_ALPHA),
+            self.consensus_list(),
+        );
+        let mut queried = HashSet::new();
+        let mut closest_known = shortlist.first().cloned();
+
+        for _ in 0..LOOKUP_MAX_STEPS {
+            let to_query: Vec<Multiaddr> = shortlist
+                .iter()
+                .filter(|addr| !queried.contains(*addr))
+                .take(LOOKUP_ALPHA)
+                .cloned()
+                .collect();
+            if to_query.is_empty() {
+                break;
+            }
+
+            let mut discovered = Vec::new();
+            for addr in &to_query {
+                queried.insert(addr.clone());
+                match self
+                    .peer_manager
+                    .find_node(addr, target, kbucket::BUCKET_SIZE as u32)
+                    .await
+                {
+                    Ok(nodes) => discovered.extend(nodes),
+                    Err(err) => debug!("lookup: FindNode to {} failed: {:?}", addr, err),
+                }
+            }
+
+            discovered.retain(|addr| self.is_valid_addr(addr));
+            shortlist.extend(discovered);
+            kbucket::sort_by_distance(target, &mut shortlist);
+            shortlist.dedup();
+            shortlist.truncate(kbucket::BUCKET_SIZE);
+
+            let new_closest = shortlist.first().cloned();
+            if new_closest == closest_known {
+                // No closer node found this round; the lookup has converged.
+                break;
+            }
+            closest_known = new_closest;
+        }
+
+        shortlist
+    }
 }
 
 impl AddressManager for DiscoveryAddressManager {
@@ -338,47 +622,198 @@ impl AddressManager for DiscoveryAddressManager {
         self.add_new_addrs(session_id, vec![addr])
     }
 
-    fn add_new_addrs(&mut self, _session_id: SessionId, addrs: Vec<Multiaddr>) {
+    fn add_new_addrs(&mut self, session_id: SessionId, addrs: Vec<Multiaddr>) {
         if addrs.is_empty() {
             return;
         }
 
+        self.evict_expired_pending();
+
         for addr in addrs.into_iter().filter(|addr| self.is_valid_addr(addr)) {
-            trace!("Add discovered address:{:?}", addr);
-            self.peer_manager.with_peer_store_mut(|peer_store| {
-                if let Err(err) = peer_store.add_addr(addr.clone()) {
-                    debug!(
-                        "Failed to add discoved address to peer_store {:?} {:?}",
-                        err, addr
-                    );
-                }
-            });
+            // Not trusted until ping/pong succeeds (see confirm_verified);
+            // otherwise an attacker could flood us with unreachable addresses.
+            if self.pending.contains_key(&addr) {
+                continue;
+            }
+            let in_flight = self
+                .pending
+                .values()
+                .filter(|pending| pending.session_id == session_id)
+                .count();
+            if in_flight >= MAX_PENDING_PER_SESSION {
+                trace!(
+                    "too many in-flight endpoint verifications from session [{}], dropping {:?}",
+                    session_id,
+                    addr
+                );
+                continue;
+            }
+
+            trace!("Challenging discovered address {:?}", addr);
+            let nonce: u64 = rand::random();
+            self.pending.insert(
+                addr.clone(),
+                PendingVerification {
+                    nonce,
+                    session_id,
+                    expires_at: Instant::now() + PING_CHALLENGE_TIMEOUT,
+                },
+            );
+            self.peer_manager.dial(addr);
         }
     }
 
-    fn misbehave(&mut self, _session_id: SessionId, _kind: Misbehavior) -> MisbehaveResult {
-        // FIXME:
-        MisbehaveResult::Disconnect
+    fn misbehave(&mut self, session_id: SessionId, kind: Misbehavior) -> MisbehaveResult {
+        let score_delta = misbehavior_score(&kind);
+        let score = self
+            .peer_manager
+            .report_misbehavior(session_id, score_delta);
+        debug!(
+            "session [{}] misbehaved: {:?} (+{}, total {})",
+            session_id, kind, score_delta, score
+        );
+
+        misbehave_result(score, self.ban_score_threshold)
     }
 
     fn get_random(&mut self, n: usize) -> Vec<Multiaddr> {
-        let fetch_random_addrs = self
-            .peer_manager
-            .with_peer_store_mut(|peer_store| peer_store.fetch_random_addrs(n));
-        let addrs = fetch_random_addrs
+        // Spread the sample across buckets instead of drawing uniformly, so
+        // the addresses we hand out give the requester diverse id-space
+        // coverage rather than whatever happened to be picked at random.
+        let addrs = self
+            .kbuckets
+            .get_random(n)
             .into_iter()
-            .filter_map(|paddr| {
-                if !self.is_valid_addr(&paddr.addr) {
-                    return None;
-                }
-                Some(paddr.addr)
-            })
-            .collect();
+            .filter(|addr| self.is_valid_addr(addr))
+            .collect::<Vec<_>>();
         trace!("discovery send random addrs: {:?}", addrs);
         addrs
     }
 
+    fn closest(&mut self, target: &[u8], n: usize) -> Vec<Multiaddr> {
+        self.kbuckets
+            .closest(target, n)
+            .into_iter()
+            .filter(|addr| self.is_valid_addr(addr))
+            .collect()
+    }
+
     fn consensus_list(&self) -> Vec<Multiaddr> {
         self.peer_manager.connected_consensus_peer()
     }
-}
\ No newline at end of file
+
+    fn wait_identified(&self, session_id: SessionId) -> IdentifyResult {
+        self.peer_manager.session_identify_result(session_id)
+    }
+
+    fn pending_challenge(&mut self, addr: &Multiaddr) -> Option<u64> {
+        self.evict_expired_pending();
+        self.pending.get(addr).map(|pending| pending.nonce)
+    }
+
+    fn confirm_verified(&mut self, addr: &Multiaddr, nonce: u64) -> bool {
+        self.evict_expired_pending();
+        match self.pending.get(addr) {
+            Some(pending) if pong_matches_challenge(pending.nonce, nonce) => {
+                self.pending.remove(addr);
+                // Proven reachable: only now does it enter the k-bucket
+                // table that get_random/closest/persist_node_table read from.
+                if let Some(peer_id) = extract_peer_id(addr) {
+                    self.kbuckets.insert(peer_id.clone(), addr.clone());
+                    self.kbuckets.record_success(&peer_id);
+                }
+                self.peer_manager.with_peer_store_mut(|peer_store| {
+                    if let Err(err) = peer_store.add_addr(addr.clone()) {
+                        debug!(
+                            "Failed to add verified address to peer_store {:?} {:?}",
+                            err, addr
+                        );
+                    }
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn decay_scores(&mut self) {
+        self.peer_manager.decay_misbehavior_scores(self.score_decay_rate);
+    }
+
+    fn persist_node_table(&self) {
+        self.peer_manager.save_node_table(self.kbuckets.snapshot());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr_for(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port)
+            .parse()
+            .expect("valid multiaddr")
+    }
+
+    #[test]
+    fn seed_shortlist_falls_back_to_consensus_list_when_table_is_empty() {
+        let consensus_list = vec![addr_for(9000)];
+        assert_eq!(seed_shortlist(vec![], consensus_list.clone()), consensus_list);
+    }
+
+    #[test]
+    fn seed_shortlist_prefers_known_closest_peers() {
+        let closest = vec![addr_for(8000)];
+        assert_eq!(seed_shortlist(closest.clone(), vec![addr_for(9000)]), closest);
+    }
+
+    #[test]
+    fn pong_only_confirms_a_matching_nonce() {
+        assert!(pong_matches_challenge(42, 42));
+        assert!(!pong_matches_challenge(42, 7));
+    }
+
+    #[test]
+    fn admission_ignores_while_identify_is_pending() {
+        assert_eq!(admission(None), Admission::Ignore);
+    }
+
+    #[test]
+    fn admission_rejects_a_different_chain_id() {
+        assert_eq!(admission(Some(false)), Admission::Reject);
+    }
+
+    #[test]
+    fn admission_proceeds_once_identified_on_our_chain() {
+        assert_eq!(admission(Some(true)), Admission::Proceed);
+    }
+
+    #[test]
+    fn misbehave_result_continues_below_threshold() {
+        assert_eq!(misbehave_result(50, DEFAULT_BAN_SCORE_THRESHOLD), MisbehaveResult::Continue);
+    }
+
+    #[test]
+    fn misbehave_result_disconnects_at_and_above_threshold() {
+        assert_eq!(
+            misbehave_result(DEFAULT_BAN_SCORE_THRESHOLD, DEFAULT_BAN_SCORE_THRESHOLD),
+            MisbehaveResult::Disconnect
+        );
+        assert_eq!(misbehave_result(150, DEFAULT_BAN_SCORE_THRESHOLD), MisbehaveResult::Disconnect);
+    }
+
+    #[test]
+    fn decay_can_pull_a_banned_score_back_below_threshold() {
+        let banned_score = DEFAULT_BAN_SCORE_THRESHOLD + 5;
+        assert_eq!(
+            misbehave_result(banned_score, DEFAULT_BAN_SCORE_THRESHOLD),
+            MisbehaveResult::Disconnect
+        );
+
+        let decayed_score = banned_score - DEFAULT_SCORE_DECAY_RATE;
+        assert_eq!(
+            misbehave_result(decayed_score, DEFAULT_BAN_SCORE_THRESHOLD),
+            MisbehaveResult::Continue
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::convert::TryFrom;
+
+use prost::{Message, Oneof};
+use tentacle::multiaddr::Multiaddr;
+
+/// Top level wire message for the discovery protocol.
+#[derive(Clone, PartialEq, Message)]
+pub struct DiscoveryMessage {
+    #[prost(oneof = "Payload", tags = "1, 2, 3, 4, 5")]
+    pub payload: Option<Payload>,
+}
+
+impl DiscoveryMessage {
+    pub fn new_get_nodes(version: u32, count: u32, listen_port: Option<u16>) -> DiscoveryMessage {
+        DiscoveryMessage {
+            payload: Some(Payload::GetNodes(GetNodes {
+                listen_port: listen_port.map(|port| ListenPort {
+                    port: u32::from(port),
+                }),
+                count,
+                version,
+            })),
+        }
+    }
+
+    pub fn new_nodes(announce: bool, items: Vec<Node>) -> DiscoveryMessage {
+        DiscoveryMessage {
+            payload: Some(Payload::Nodes(Nodes { announce, items })),
+        }
+    }
+
+    pub fn new_find_node(target: Vec<u8>, count: u32) -> DiscoveryMessage {
+        DiscoveryMessage {
+            payload: Some(Payload::FindNode(FindNode { target, count })),
+        }
+    }
+
+    pub fn new_ping(nonce: u64) -> DiscoveryMessage {
+        DiscoveryMessage {
+            payload: Some(Payload::Ping(Ping { nonce })),
+        }
+    }
+
+    pub fn new_pong(nonce: u64) -> DiscoveryMessage {
+        DiscoveryMessage {
+            payload: Some(Payload::Pong(Pong { nonce })),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+pub enum Payload {
+    #[prost(message, tag = "1")]
+    GetNodes(GetNodes),
+    #[prost(message, tag = "2")]
+    Nodes(Nodes),
+    #[prost(message, tag = "3")]
+    FindNode(FindNode),
+    #[prost(message, tag = "4")]
+    Ping(Ping),
+    #[prost(message, tag = "5")]
+    Pong(Pong),
+}
+
+/// Endpoint-verification challenge: "echo this nonce back and I'll believe
+/// you're really reachable at the address you claim". Sent to a freshly
+/// dialed address before any of its advertised addresses are persisted.
+#[derive(Clone, PartialEq, Message)]
+pub struct Ping {
+    #[prost(uint64, tag = "1")]
+    pub nonce: u64,
+}
+
+/// Reply to a `Ping`, echoing its nonce.
+#[derive(Clone, PartialEq, Message)]
+pub struct Pong {
+    #[prost(uint64, tag = "1")]
+    pub nonce: u64,
+}
+
+/// Targeted lookup: "who do you know that's closest to `target`?". Answered
+/// with a `Nodes` message carrying up to `count` peers.
+#[derive(Clone, PartialEq, Message)]
+pub struct FindNode {
+    #[prost(bytes = "vec", tag = "1")]
+    pub target: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub count: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetNodes {
+    #[prost(message, optional, tag = "1")]
+    pub listen_port: Option<ListenPort>,
+    #[prost(uint32, tag = "2")]
+    pub count: u32,
+    #[prost(uint32, tag = "3")]
+    pub version: u32,
+}
+
+/// Wrapper around the remote's advertised listen port; wrapped in its own
+/// message so that "no listen port yet" (outbound-only session) can be told
+/// apart from "port 0".
+#[derive(Clone, PartialEq, Message)]
+pub struct ListenPort {
+    #[prost(uint32, tag = "1")]
+    pub port: u32,
+}
+
+impl ListenPort {
+    pub fn listen_port(&self) -> Option<u16> {
+        u16::try_from(self.port).ok()
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Node {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub addrs: Vec<Vec<u8>>,
+}
+
+impl Node {
+    pub fn with_addrs(addrs: Vec<Multiaddr>) -> Node {
+        Node {
+            addrs: addrs.into_iter().map(|addr| addr.to_vec()).collect(),
+        }
+    }
+
+    pub fn addrs(&self) -> Vec<Multiaddr> {
+        self.addrs
+            .iter()
+            .filter_map(|raw| Multiaddr::try_from(raw.clone()).ok())
+            .collect()
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Nodes {
+    #[prost(bool, tag = "1")]
+    pub announce: bool,
+    #[prost(message, repeated, tag = "2")]
+    pub items: Vec<Node>,
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use log::debug;
+use prost::Message;
+use tentacle::{
+    bytes,
+    context::{ProtocolContext, ProtocolContextMutRef},
+    multiaddr::{Multiaddr, Protocol},
+    SessionId,
+};
+
+use super::{
+    addr::{AddrKnown, AddressManager},
+    protocol::DiscoveryMessage,
+};
+
+/// Discovery protocol version from which a session's listen address may be
+/// reused for outbound dialing (and therefore announced as-is, instead of
+/// only ever the address we saw the session connect *from*).
+pub(crate) const REUSE_PORT_VERSION: u32 = 1;
+
+/// The remote address we'd announce for a session, and whether it has been
+/// confirmed as the peer's real listen address yet.
+#[derive(Debug, Clone)]
+pub enum RemoteAddress {
+    /// The address the session connected from; not yet known to be dialable.
+    Init(Multiaddr),
+    /// Confirmed (or assumed, on newer protocol versions) to be the peer's
+    /// listen address, and therefore safe to announce to others.
+    Listen(Multiaddr),
+}
+
+impl RemoteAddress {
+    pub fn to_inner(&self) -> &Multiaddr {
+        match self {
+            RemoteAddress::Init(addr) | RemoteAddress::Listen(addr) => addr,
+        }
+    }
+
+    /// Replace the port component with the one the peer told us it listens on.
+    pub fn update_port(&mut self, port: u16) {
+        let addr = match self {
+            RemoteAddress::Init(addr) | RemoteAddress::Listen(addr) => addr,
+        };
+        let mut new_addr = Multiaddr::empty();
+        for proto in addr.iter() {
+            match proto {
+                Protocol::Tcp(_) => new_addr.push(Protocol::Tcp(port)),
+                other => new_addr.push(other),
+            }
+        }
+        *addr = new_addr;
+    }
+
+    pub fn change_to_listen(&mut self) {
+        if let RemoteAddress::Init(addr) = self {
+            *self = RemoteAddress::Listen(addr.clone());
+        }
+    }
+}
+
+/// Per-session discovery state: what we've exchanged with this peer so far,
+/// and what's still queued to announce to it.
+pub struct SessionState {
+    pub remote_addr: RemoteAddress,
+    pub addr_known: AddrKnown,
+    pub received_get_nodes: bool,
+    pub received_nodes: bool,
+    pub announce_multiaddrs: Vec<Multiaddr>,
+    /// `None` until the identify handshake completes, then `Some(same_chain)`.
+    pub identified: Option<bool>,
+    timer: Instant,
+}
+
+impl SessionState {
+    pub fn new<M: AddressManager>(context: ProtocolContextMutRef, _addr_mgr: &M) -> SessionState {
+        let session = context.session;
+        SessionState {
+            remote_addr: RemoteAddress::Init(session.address.clone()),
+            addr_known: AddrKnown::default(),
+            received_get_nodes: false,
+            received_nodes: false,
+            announce_multiaddrs: Vec::new(),
+            identified: None,
+            timer: Instant::now(),
+        }
+    }
+
+    /// Flush any queued announce addresses to this session as a `Nodes`
+    /// message, if there are any.
+    pub fn send_messages(&mut self, context: &mut ProtocolContext, session_id: SessionId) {
+        if self.announce_multiaddrs.is_empty() {
+            return;
+        }
+
+        let items = self
+            .announce_multiaddrs
+            .drain(..)
+            .map(|addr| super::Node::with_addrs(vec![addr]))
+            .collect::<Vec<_>>();
+        let msg = DiscoveryMessage::new_nodes(true, items);
+
+        let mut buf = bytes::BytesMut::with_capacity(msg.encoded_len());
+        msg.encode(&mut buf).expect("encode discovery message");
+        if context
+            .send_message_to(session_id, context.proto_id, buf.freeze())
+            .is_err()
+        {
+            debug!("{:?} send discovery announce nodes fail", session_id);
+        }
+    }
+
+    /// If `interval` has elapsed since the last announce, resets the timer
+    /// and returns this session's remote address as a candidate to announce.
+    pub fn check_timer(&mut self, now: Instant, interval: Duration) -> Option<&Multiaddr> {
+        if now.saturating_duration_since(self.timer) >= interval {
+            self.timer = now;
+            Some(self.remote_addr.to_inner())
+        } else {
+            None
+        }
+    }
+}